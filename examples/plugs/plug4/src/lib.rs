@@ -5,6 +5,10 @@ pub extern "C" fn __name() -> *const u8 {
 
 extern "C" {
     fn print(a: i32);
+}
+
+#[link(wasm_import_module = "plug2")]
+extern "C" {
     fn plug2(a: i32);
 }
 
@@ -1,327 +1,817 @@
-use std::{collections::HashMap, path::Path};
-
-use wasmtime::{
-    Engine, Extern, Func, Instance, IntoFunc, Linker, Module, Store, TypedFunc, UnknownImportError,
-    Val, ValType, WasmParams, WasmResults,
-};
-
-const DEPS_EXPORT: &str = "__deps";
-const INIT_EXPORT: &str = "__init";
-
-pub type PlugId = usize;
-
-pub struct PlugContext<T>(pub PlugId, pub T);
-
-pub struct Plug<T> {
-    pub id: PlugId,
-    pub module: Module,
-    pub linker: Linker<PlugContext<T>>,
-    pub instance: Option<Instance>,
-    pub deps: Vec<String>,
-    pub exports: Vec<String>,
-    pub imports: Vec<String>,
-}
-
-pub struct PlugMetadata {
-    pub deps: Vec<String>,
-    pub exports: Vec<String>,
-    pub imports: Vec<String>,
-}
-
-pub struct PlugsHostFns {
-    pub fns: Vec<(String, Extern)>,
-}
-
-pub struct Plugs<T> {
-    pub store: Store<PlugContext<T>>,
-    pub items: HashMap<String, Plug<T>>,
-    pub order: Vec<String>,
-    pub host_fns: PlugsHostFns,
-}
-
-impl<T> Plugs<T> {
-    /// Create a new `Plugs` with a `wasmtime::Engine` and state
-    pub fn new(engine: &Engine, state: T) -> Self {
-        Self {
-            store: Store::new(engine, PlugContext(0, state)),
-            items: HashMap::new(),
-            order: Vec::new(),
-            host_fns: PlugsHostFns { fns: Vec::new() },
-        }
-    }
-
-    pub fn add_host_fn<Params, Results>(
-        &mut self,
-        name: String,
-        func: impl IntoFunc<PlugContext<T>, Params, Results>,
-    ) {
-        let func = Func::wrap(&mut self.store, func);
-        let func = Into::<Extern>::into(func);
-        self.host_fns.fns.push((name, func));
-    }
-
-    pub fn link_host(&mut self, linker: &mut Linker<PlugContext<T>>) -> wasmtime::Result<()> {
-        for (name, func) in self.host_fns.fns.iter() {
-            linker.define(&mut self.store, "env", name, func.clone())?;
-        }
-        Ok(())
-    }
-
-    /// Extract metadata from the specified module by instantiating a temporary instance and running the
-    /// necessary reserved functions (such as `deps`) for metadata extraction.
-    pub fn extract_metadata(
-        &mut self,
-        engine: &Engine,
-        module: &Module,
-    ) -> wasmtime::Result<PlugMetadata> {
-        let mut linker = Linker::new(engine);
-
-        let mut imports = Vec::new();
-        let instance = loop {
-            match linker.instantiate(&mut self.store, &module) {
-                Ok(inst) => break inst,
-                Err(e) => {
-                    let e: UnknownImportError = e.downcast()?;
-                    let ftype = e.ty().func().unwrap().clone();
-                    let result_types = ftype.results().collect::<Vec<_>>();
-                    linker.func_new("env", e.name(), ftype, move |_, _, results| {
-                        for (i, res_type) in result_types.iter().enumerate() {
-                            results[i] = match res_type {
-                                ValType::I32 => Val::I32(0),
-                                ValType::I64 => Val::I64(0),
-                                ValType::F32 => Val::F32(0f32.to_bits()),
-                                ValType::F64 => Val::F64(0f64.to_bits()),
-                                ValType::V128 => Val::V128(0u128.into()),
-                                ValType::Ref(r) => Val::null_ref(r.heap_type()),
-                            };
-                        }
-
-                        Ok(())
-                    })?;
-                    let imp = e.name().to_string();
-                    let is_host_fn = self.host_fns.fns.iter().any(|(n, _)| imp.eq(n));
-                    if !is_host_fn {
-                        imports.push(e.name().to_string());
-                    }
-                    continue;
-                }
-            }
-        };
-
-        // TODO: The plugin name could also be extracted in a similar way instead of
-        // relying on the file name. The current file name approach makes the system simpler
-        // but I think I will switch to a `name` export in the future.
-
-        // Extract dependencies (optional)
-        let mut deps = Vec::new();
-        if let Ok(deps_fn) = instance.get_typed_func::<(), u32>(&mut self.store, DEPS_EXPORT) {
-            let mut deps_ptr = deps_fn.call(&mut self.store, ())?;
-            let memory = {
-                if let Some(m) = instance.get_memory(&mut self.store, "memory") {
-                    m
-                } else {
-                    return Err(wasmtime::Error::msg("Couldn't find 'memory' export"));
-                }
-            };
-            let mut deps_buf = vec![0u8];
-            deps.push(String::new());
-            memory.read(&mut self.store, deps_ptr as usize, &mut deps_buf)?;
-            while deps_buf[0] != 0 {
-                let c = deps_buf[0] as char;
-                if c == ';' {
-                    deps.push(String::new());
-                } else {
-                    deps.last_mut().unwrap().push(c);
-                }
-                deps_ptr += 1;
-                memory.read(&mut self.store, deps_ptr as usize, &mut deps_buf)?;
-            }
-        }
-        let exports = module.exports().map(|e| e.name().to_string()).collect();
-        Ok(PlugMetadata {
-            deps,
-            exports,
-            imports,
-        })
-    }
-
-    /// Add plug (without linking except host functions)
-    pub fn add(&mut self, file_path: &str, engine: &Engine) -> wasmtime::Result<()> {
-        let fp = Path::new(file_path);
-        let ext = fp.extension().unwrap();
-        let ext_len = ext.len();
-        let name = fp.file_name().unwrap().to_str().unwrap();
-        let len = name.len();
-        let name = &name[..len - ext_len - 1];
-        let module = Module::from_file(engine, file_path)?;
-
-        let metadata = self.extract_metadata(engine, &module)?;
-
-        let mut linker = Linker::new(engine);
-
-        // Link host functions
-        self.link_host(&mut linker)?;
-
-        self.items.insert(
-            name.to_string(),
-            Plug {
-                id: self.order.len(),
-                module,
-                linker,
-                instance: None,
-                deps: metadata.deps,
-                exports: metadata.exports,
-                imports: metadata.imports,
-            },
-        );
-        self.order.push(name.to_string());
-
-        Ok(())
-    }
-
-    /// Link all plugs, load order is important (TODO: auto sorting)
-    /// and circular dependencies are disallowed (won't change, TODO: report as error)
-    pub fn link(&mut self) -> wasmtime::Result<()> {
-        // TODO: perhaps sort the plugins before linking them so that all plugins are guaranteed to be loaded after their dependencies
-        // this could also be a chance for us to detect circular dependencies and throw an error in that case since they are disallowed
-        //
-        // Circular dependencies are disallowed because we can't easily detect which _symbol_ depends on which, we only know which plugin
-        // depends on which symbols and that isn't really enough to properly resolve all cases. If we were to just use that info, there
-        // could be some edge case where the linker doesn't properly link everything especially if the dependency graph is very
-        // convoluted and the circular dependency is deep within the dependency tree.
-        for name in self.order.iter() {
-            let p = self.items.get_mut(name.as_str()).unwrap();
-            let deps = p.deps.clone();
-            let mut imports = p.imports.clone();
-            let mut to_import = Vec::new();
-
-            #[cfg(debug_assertions)]
-            println!("\n[Plugs::link]: '{name}' has {deps:?} as dependencies");
-
-            if imports.len() > 0 {
-                for dep_name in deps.iter() {
-                    if let Some(p_dep) = self.items.get_mut(dep_name) {
-                        imports = {
-                            let mut res = Vec::new();
-                            for imp in imports {
-                                let exists = p_dep.exports.contains(&imp);
-                                if exists {
-                                    let inst = if let Some(inst) = &p_dep.instance {
-                                        inst
-                                    } else {
-                                        return Err(wasmtime::Error::msg(format!("Dependency '{dep_name}' in plugin '{name}' hasn't been instantiated yet")));
-                                    };
-
-                                    let export = if let Some(e) =
-                                        inst.get_export(&mut self.store, &imp)
-                                    {
-                                        e
-                                    } else {
-                                        return Err(wasmtime::Error::msg(format!("Dependency '{dep_name}' doesn't have export '{imp}' required by plugin '{name}'")));
-                                    };
-
-                                    #[cfg(debug_assertions)]
-                                    println!("[Plugs::link]: Will define '{imp}' from '{dep_name}' in '{name}'");
-
-                                    to_import.push((imp, export));
-                                } else {
-                                    res.push(imp);
-                                }
-                            }
-
-                            res
-                        };
-                    } else {
-                        return Err(wasmtime::Error::msg(format!(
-                            "'{dep_name}' is not a valid dependency"
-                        )));
-                    }
-                }
-            }
-
-            let p = self.items.get_mut(name.as_str()).unwrap();
-
-            if imports.len() > 0 {
-                return Err(wasmtime::Error::msg(format!(
-                    "Plugin '{name}' has unresolved imports: {:?}",
-                    imports
-                )));
-            }
-
-            for (imp, export) in to_import {
-                p.linker.define(&mut self.store, "env", &imp, export)?;
-            }
-
-            p.instance = Some(p.linker.instantiate(&mut self.store, &p.module)?);
-        }
-        Ok(())
-    }
-
-    pub fn init(&mut self) -> wasmtime::Result<()> {
-        let names = self.order.clone();
-
-        for name in names {
-            if let Ok((id, init_fn)) = self.get_func_with_id::<(), ()>(&name, INIT_EXPORT) {
-                self.set_current_id(id);
-                init_fn.call(&mut self.store, ())?;
-            }
-        }
-
-        Ok(())
-    }
-
-    /// Convenience function calling function in a plugin and setting the plugin's id as the current
-    pub fn call<P: WasmParams, R: WasmResults>(
-        &mut self,
-        plug: &str,
-        func: &str,
-        params: P,
-    ) -> wasmtime::Result<R> {
-        let (id, f) = self.get_func_with_id(plug, func)?;
-        self.set_current_id(id);
-        f.call(&mut self.store, params)
-    }
-
-    /// Must be set before calling any function
-    pub fn set_current_id(&mut self, plugin_id: PlugId) {
-        self.store.data_mut().0 = plugin_id;
-    }
-
-    /// Gets id of plugin by name
-    pub fn get_plug_id(&self, name: &str) -> Option<PlugId> {
-        if let Some(p) = self.items.get(name) {
-            return Some(p.id);
-        }
-        None
-    }
-
-    /// Looks up a function in the specified plugin and returns the id of the plugin and the function
-    pub fn get_func_with_id<P: WasmParams, R: WasmResults>(
-        &mut self,
-        plug: &str,
-        func: &str,
-    ) -> wasmtime::Result<(PlugId, TypedFunc<P, R>)> {
-        if let Some(p) = self.items.get(plug) {
-            if let Some(inst) = &p.instance {
-                inst.get_typed_func::<P, R>(&mut self.store, func)
-                    .map(|f| (p.id, f))
-            } else {
-                Err(wasmtime::Error::msg(format!(
-                    "Plugin '{plug}' hasn't been instantiated yet"
-                )))
-            }
-        } else {
-            Err(wasmtime::Error::msg(format!(
-                "Couldn't find function '{func}' in plugin '{plug}'"
-            )))
-        }
-    }
-
-    pub fn get_plug_mut(&mut self, name: &str) -> Option<&mut Plug<T>> {
-        self.items.get_mut(name)
-    }
-
-    pub fn get_plug(&self, name: &str) -> Option<&Plug<T>> {
-        self.items.get(name)
-    }
-}
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use wasmtime::{
+    Caller, Engine, Extern, Func, Instance, IntoFunc, Linker, Memory, Module, Store, TypedFunc,
+    UnknownImportError, Val, ValType, WasmParams, WasmResults,
+};
+
+const DEPS_EXPORT: &str = "__deps";
+const NAME_EXPORT: &str = "__name";
+const INIT_EXPORT: &str = "__init";
+const ALLOC_EXPORT: &str = "__alloc";
+const MEMORY_EXPORT: &str = "memory";
+
+pub type PlugId = usize;
+
+pub struct PlugContext<T>(pub PlugId, pub T);
+
+/// A single entry of a plugin's `__deps` list, naming a module it depends on and which of
+/// that module's exports it's allowed to pull in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DepSpec {
+    /// `module` — import by name, matched against whatever of `module`'s exports the
+    /// plugin happens to import (the original, unqualified behavior).
+    All(String),
+    /// `module::symbol` — only `symbol` may be pulled in from `module`.
+    Item { module: String, symbol: String },
+}
+
+impl DepSpec {
+    /// Parses a single `;`-separated `__deps` entry, accepting `module`, `module::*` (both
+    /// equivalent to [`DepSpec::All`]) and `module::symbol` ([`DepSpec::Item`]).
+    pub fn parse(entry: &str) -> DepSpec {
+        match entry.split_once("::") {
+            None => DepSpec::All(entry.to_string()),
+            Some((module, "*")) => DepSpec::All(module.to_string()),
+            Some((module, symbol)) => DepSpec::Item {
+                module: module.to_string(),
+                symbol: symbol.to_string(),
+            },
+        }
+    }
+
+    /// The module name this spec refers to, regardless of variant.
+    pub fn module(&self) -> &str {
+        match self {
+            DepSpec::All(module) => module,
+            DepSpec::Item { module, .. } => module,
+        }
+    }
+}
+
+pub struct Plug<T> {
+    pub id: PlugId,
+    pub module: Module,
+    pub linker: Linker<PlugContext<T>>,
+    pub instance: Option<Instance>,
+    pub deps: Vec<DepSpec>,
+    pub exports: Vec<String>,
+    /// `(module, name)` pairs for every import the plugin's wasm module declares that isn't
+    /// satisfied by a host function, in the order they were discovered.
+    pub imports: Vec<(String, String)>,
+}
+
+pub struct PlugMetadata {
+    /// The plugin's identity as read from its `__name` export, or `None` if it doesn't
+    /// define one (in which case `add` falls back to the plugin file's stem).
+    pub name: Option<String>,
+    pub deps: Vec<DepSpec>,
+    pub exports: Vec<String>,
+    pub imports: Vec<(String, String)>,
+}
+
+pub struct PlugsHostFns {
+    pub fns: Vec<(String, Extern)>,
+    /// The wasmtime module name host functions are defined under. Defaults to `"env"`.
+    pub module: String,
+}
+
+pub struct Plugs<T> {
+    pub store: Store<PlugContext<T>>,
+    pub items: HashMap<String, Plug<T>>,
+    pub order: Vec<String>,
+    pub host_fns: PlugsHostFns,
+    /// Directory `add` caches compiled modules and metadata under, if set via
+    /// [`Plugs::enable_cache`]. `None` (the default) means caching is disabled.
+    pub cache_dir: Option<PathBuf>,
+}
+
+/// On-disk sidecar written under [`Plugs::cache_dir`] for a cached plugin, holding everything `add` needs to skip
+/// recompilation and metadata re-extraction: wasmtime's serialized precompiled module plus
+/// the resolved deps/exports/imports, keyed by the source file's content hash.
+#[derive(Serialize, Deserialize)]
+struct PlugCacheEntry {
+    content_hash: u64,
+    precompiled: Vec<u8>,
+    name: Option<String>,
+    deps: Vec<DepSpec>,
+    exports: Vec<String>,
+    imports: Vec<(String, String)>,
+}
+
+/// Hashes a plugin file's contents for use as a cache key.
+fn hash_file_contents(bytes: &[u8]) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Loads and validates a plugin's cache sidecar, returning `None` if there's no cache file
+/// yet or its `content_hash` no longer matches the plugin's current contents.
+fn load_plug_cache(
+    engine: &Engine,
+    cache_path: &Path,
+    content_hash: u64,
+) -> wasmtime::Result<Option<(Module, PlugMetadata)>> {
+    let Ok(bytes) = std::fs::read(cache_path) else {
+        return Ok(None);
+    };
+    let entry: PlugCacheEntry = match bincode::deserialize(&bytes) {
+        Ok(entry) => entry,
+        Err(_) => return Ok(None),
+    };
+    if entry.content_hash != content_hash {
+        return Ok(None);
+    }
+
+    // Safety: the precompiled bytes were produced by `Module::serialize` for this same
+    // wasmtime `Engine` version by `write_plug_cache`, and are re-validated against the
+    // plugin's content hash above. `deserialize` itself still rejects bytes produced by a
+    // different wasmtime build/target/CPU (e.g. after the host binary was upgraded), which
+    // we treat the same as any other unusable cache: fall through to recompiling.
+    let module = match unsafe { Module::deserialize(engine, &entry.precompiled) } {
+        Ok(module) => module,
+        Err(_) => return Ok(None),
+    };
+    Ok(Some((
+        module,
+        PlugMetadata {
+            name: entry.name,
+            deps: entry.deps,
+            exports: entry.exports,
+            imports: entry.imports,
+        },
+    )))
+}
+
+/// Writes a plugin's compiled module and metadata to its cache sidecar.
+fn write_plug_cache(
+    cache_path: &Path,
+    content_hash: u64,
+    module: &Module,
+    metadata: &PlugMetadata,
+) -> wasmtime::Result<()> {
+    let entry = PlugCacheEntry {
+        content_hash,
+        precompiled: module.serialize()?,
+        name: metadata.name.clone(),
+        deps: metadata.deps.clone(),
+        exports: metadata.exports.clone(),
+        imports: metadata.imports.clone(),
+    };
+    let bytes = bincode::serialize(&entry)?;
+    std::fs::write(cache_path, bytes)?;
+    Ok(())
+}
+
+/// Color used while DFS-walking the dependency graph in [`toposort`] to tell apart
+/// in-progress and finished nodes (the classic white/gray/black scheme); unvisited
+/// ("white") nodes are represented by their absence from the `colors` map.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Gray,
+    Black,
+}
+
+/// DFS visit used by [`toposort`]: pushes `name` onto `stack` while it's gray (on the
+/// current DFS path) so that if a gray node is revisited, the slice of `stack` between
+/// the two occurrences is the offending cycle.
+fn visit_dep<T>(
+    name: &str,
+    items: &HashMap<String, Plug<T>>,
+    colors: &mut HashMap<String, Color>,
+    stack: &mut Vec<String>,
+    sorted: &mut Vec<String>,
+) -> wasmtime::Result<()> {
+    match colors.get(name) {
+        Some(Color::Black) => return Ok(()),
+        Some(Color::Gray) => {
+            let start = stack.iter().position(|n| n == name).unwrap();
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(name.to_string());
+            return Err(wasmtime::Error::msg(format!(
+                "Circular dependency detected: {}",
+                cycle.join(" -> ")
+            )));
+        }
+        _ => {}
+    }
+
+    colors.insert(name.to_string(), Color::Gray);
+    stack.push(name.to_string());
+
+    let p = items.get(name).unwrap();
+    for dep in p.deps.iter() {
+        let dep_name = dep.module();
+        if !items.contains_key(dep_name) {
+            return Err(wasmtime::Error::msg(format!(
+                "Plugin '{name}' depends on '{dep_name}' which doesn't exist"
+            )));
+        }
+        visit_dep(dep_name, items, colors, stack, sorted)?;
+    }
+
+    stack.pop();
+    colors.insert(name.to_string(), Color::Black);
+    sorted.push(name.to_string());
+    Ok(())
+}
+
+/// Resolves the `memory` export of whichever plugin instance is calling into a host
+/// function, for use by [`Plugs::add_host_fn_serde`].
+fn get_caller_memory<T>(caller: &mut Caller<'_, PlugContext<T>>) -> wasmtime::Result<Memory> {
+    caller
+        .get_export(MEMORY_EXPORT)
+        .and_then(|e| e.into_memory())
+        .ok_or_else(|| wasmtime::Error::msg(format!("Couldn't find '{MEMORY_EXPORT}' export")))
+}
+
+/// Packs a `(ptr, len)` pair into a single `i64` (ptr in the high 32 bits, len in the low
+/// 32) so it can cross the host/plugin boundary as one wasm scalar return value.
+fn pack_ptr_len(ptr: u32, len: u32) -> i64 {
+    ((ptr as i64) << 32) | len as i64
+}
+
+/// Inverse of [`pack_ptr_len`].
+fn unpack_ptr_len(packed: i64) -> (u32, u32) {
+    ((packed >> 32) as u32, packed as u32)
+}
+
+impl<T> Plugs<T> {
+    /// Create a new `Plugs` with a `wasmtime::Engine` and state
+    pub fn new(engine: &Engine, state: T) -> Self {
+        Self {
+            store: Store::new(engine, PlugContext(0, state)),
+            items: HashMap::new(),
+            order: Vec::new(),
+            host_fns: PlugsHostFns {
+                fns: Vec::new(),
+                module: "env".to_string(),
+            },
+            cache_dir: None,
+        }
+    }
+
+    /// Sets the wasmtime module name host functions are exposed under (`"env"` by default).
+    /// Plugins call into the host by importing from this module name.
+    pub fn set_host_module(&mut self, module: String) {
+        self.host_fns.module = module;
+    }
+
+    /// Opts into caching compiled modules and extracted metadata under `dir`, keyed by each
+    /// plugin file's path and content hash. Once enabled, `add` skips recompilation and
+    /// `__deps`/import extraction entirely for plugins whose cache is still valid.
+    pub fn enable_cache(&mut self, dir: impl Into<PathBuf>) {
+        self.cache_dir = Some(dir.into());
+    }
+
+    pub fn add_host_fn<Params, Results>(
+        &mut self,
+        name: String,
+        func: impl IntoFunc<PlugContext<T>, Params, Results>,
+    ) {
+        let func = Func::wrap(&mut self.store, func);
+        let func = Into::<Extern>::into(func);
+        self.host_fns.fns.push((name, func));
+    }
+
+    /// Registers a host function that exchanges arbitrary `serde`-serializable values with
+    /// the calling plugin instead of raw wasm scalars. The plugin writes a bincode-encoded
+    /// `In` into its own `memory` export and passes `(ptr, len)`; the wrapper reads it back
+    /// out of the caller's memory (via `Caller::get_export`, which always resolves against
+    /// whichever plugin is making the call), deserializes it, runs `func`, then serializes
+    /// the `Out` result through the caller's `__alloc` export and returns the result's
+    /// `(ptr, len)` packed into a single `i64` (ptr in the high 32 bits, len in the low 32).
+    pub fn add_host_fn_serde<In, Out>(&mut self, name: String, func: impl Fn(In) -> Out + Send + Sync + 'static)
+    where
+        In: DeserializeOwned + 'static,
+        Out: Serialize + 'static,
+    {
+        let wrapped = move |mut caller: Caller<'_, PlugContext<T>>,
+                            ptr: i32,
+                            len: i32|
+              -> wasmtime::Result<i64> {
+            let memory = get_caller_memory(&mut caller)?;
+
+            // `len as u32 as usize` turns a negative (or otherwise bogus) `len` into a huge
+            // value rather than sign-extending it, so capping it against the plugin's own
+            // memory size rejects it with an `Err` here instead of aborting the allocator.
+            let len = len as u32 as usize;
+            if len > memory.data_size(&caller) {
+                return Err(wasmtime::Error::msg(format!(
+                    "Plugin passed invalid length {len} larger than its own memory"
+                )));
+            }
+            let mut buf = vec![0u8; len];
+            memory.read(&caller, ptr as usize, &mut buf)?;
+            let input: In = bincode::deserialize(&buf)?;
+
+            let output = func(input);
+            let bytes = bincode::serialize(&output)?;
+
+            let alloc = caller
+                .get_export(ALLOC_EXPORT)
+                .and_then(|e| e.into_func())
+                .ok_or_else(|| {
+                    wasmtime::Error::msg(format!("Couldn't find '{ALLOC_EXPORT}' export"))
+                })?
+                .typed::<u32, u32>(&caller)?;
+            let out_ptr = alloc.call(&mut caller, bytes.len() as u32)?;
+            memory.write(&mut caller, out_ptr as usize, &bytes)?;
+
+            Ok(pack_ptr_len(out_ptr, bytes.len() as u32))
+        };
+
+        self.add_host_fn(name, wrapped);
+    }
+
+    pub fn link_host(&mut self, linker: &mut Linker<PlugContext<T>>) -> wasmtime::Result<()> {
+        let module = self.host_fns.module.clone();
+        for (name, func) in self.host_fns.fns.iter() {
+            linker.define(&mut self.store, &module, name, func.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Extract metadata from the specified module by instantiating a temporary instance and running the
+    /// necessary reserved functions (such as `deps`) for metadata extraction.
+    pub fn extract_metadata(
+        &mut self,
+        engine: &Engine,
+        module: &Module,
+    ) -> wasmtime::Result<PlugMetadata> {
+        let mut linker = Linker::new(engine);
+
+        let mut imports = Vec::new();
+        let instance = loop {
+            match linker.instantiate(&mut self.store, &module) {
+                Ok(inst) => break inst,
+                Err(e) => {
+                    let e: UnknownImportError = e.downcast()?;
+                    let ftype = e.ty().func().unwrap().clone();
+                    let result_types = ftype.results().collect::<Vec<_>>();
+                    let imp_module = e.module().to_string();
+                    let imp_name = e.name().to_string();
+                    linker.func_new(&imp_module, &imp_name, ftype, move |_, _, results| {
+                        for (i, res_type) in result_types.iter().enumerate() {
+                            results[i] = match res_type {
+                                ValType::I32 => Val::I32(0),
+                                ValType::I64 => Val::I64(0),
+                                ValType::F32 => Val::F32(0f32.to_bits()),
+                                ValType::F64 => Val::F64(0f64.to_bits()),
+                                ValType::V128 => Val::V128(0u128.into()),
+                                ValType::Ref(r) => Val::null_ref(r.heap_type()),
+                            };
+                        }
+
+                        Ok(())
+                    })?;
+                    let is_host_fn = imp_module == self.host_fns.module
+                        && self.host_fns.fns.iter().any(|(n, _)| imp_name.eq(n));
+                    if !is_host_fn {
+                        imports.push((imp_module, imp_name));
+                    }
+                    continue;
+                }
+            }
+        };
+
+        // Extract identity (optional). A `__name` export takes precedence over the plugin
+        // file's stem, since `__deps` entries reference plugins by this name and renaming
+        // the `.wasm` file would otherwise silently break every dependent's linkage.
+        let mut name = None;
+        if let Ok(name_fn) = instance.get_typed_func::<(), u32>(&mut self.store, NAME_EXPORT) {
+            let mut name_ptr = name_fn.call(&mut self.store, ())?;
+            let memory = {
+                if let Some(m) = instance.get_memory(&mut self.store, MEMORY_EXPORT) {
+                    m
+                } else {
+                    return Err(wasmtime::Error::msg("Couldn't find 'memory' export"));
+                }
+            };
+            let mut name_buf = vec![0u8];
+            let mut name_bytes = Vec::new();
+            memory.read(&mut self.store, name_ptr as usize, &mut name_buf)?;
+            while name_buf[0] != 0 {
+                name_bytes.push(name_buf[0]);
+                name_ptr += 1;
+                memory.read(&mut self.store, name_ptr as usize, &mut name_buf)?;
+            }
+            name = Some(String::from_utf8_lossy(&name_bytes).into_owned());
+        }
+
+        // Extract dependencies (optional). Each `;`-separated entry is either a bare
+        // `module` (or `module::*`), matched by [`DepSpec::All`], or a `module::symbol`
+        // entry restricting the import to that single symbol ([`DepSpec::Item`]).
+        let mut deps_raw = Vec::new();
+        if let Ok(deps_fn) = instance.get_typed_func::<(), u32>(&mut self.store, DEPS_EXPORT) {
+            let mut deps_ptr = deps_fn.call(&mut self.store, ())?;
+            let memory = {
+                if let Some(m) = instance.get_memory(&mut self.store, MEMORY_EXPORT) {
+                    m
+                } else {
+                    return Err(wasmtime::Error::msg("Couldn't find 'memory' export"));
+                }
+            };
+            let mut deps_buf = vec![0u8];
+            deps_raw.push(String::new());
+            memory.read(&mut self.store, deps_ptr as usize, &mut deps_buf)?;
+            while deps_buf[0] != 0 {
+                let c = deps_buf[0] as char;
+                if c == ';' {
+                    deps_raw.push(String::new());
+                } else {
+                    deps_raw.last_mut().unwrap().push(c);
+                }
+                deps_ptr += 1;
+                memory.read(&mut self.store, deps_ptr as usize, &mut deps_buf)?;
+            }
+        }
+        let deps = deps_raw.iter().map(|entry| DepSpec::parse(entry)).collect();
+        let exports = module.exports().map(|e| e.name().to_string()).collect();
+        Ok(PlugMetadata {
+            name,
+            deps,
+            exports,
+            imports,
+        })
+    }
+
+    /// Add plug (without linking except host functions)
+    pub fn add(&mut self, file_path: &str, engine: &Engine) -> wasmtime::Result<()> {
+        let fp = Path::new(file_path);
+        let ext = fp.extension().unwrap();
+        let ext_len = ext.len();
+        let file_stem = fp.file_name().unwrap().to_str().unwrap();
+        let len = file_stem.len();
+        let file_stem = &file_stem[..len - ext_len - 1];
+
+        // Keyed by the plugin's full path (so plugins that share a file stem in different
+        // directories don't collide) plus its content hash, checked once the cache is read.
+        let cache_path = self.cache_dir.as_ref().map(|dir| {
+            let normalized_path = fp.canonicalize().unwrap_or_else(|_| fp.to_path_buf());
+            let path_hash = hash_file_contents(normalized_path.to_string_lossy().as_bytes());
+            dir.join(format!("{file_stem}-{path_hash:016x}.plugcache"))
+        });
+
+        let (module, metadata) = if let Some(cache_path) = &cache_path {
+            let content_hash = hash_file_contents(&std::fs::read(file_path)?);
+            if let Some(cached) = load_plug_cache(engine, cache_path, content_hash)? {
+                cached
+            } else {
+                let module = Module::from_file(engine, file_path)?;
+                let metadata = self.extract_metadata(engine, &module)?;
+                write_plug_cache(cache_path, content_hash, &module, &metadata)?;
+                (module, metadata)
+            }
+        } else {
+            let module = Module::from_file(engine, file_path)?;
+            let metadata = self.extract_metadata(engine, &module)?;
+            (module, metadata)
+        };
+
+        // A `__name` export is the plugin's stable identity; fall back to the file stem
+        // only when the plugin doesn't define one.
+        let name = metadata.name.unwrap_or_else(|| file_stem.to_string());
+
+        let mut linker = Linker::new(engine);
+
+        // Link host functions
+        self.link_host(&mut linker)?;
+
+        self.items.insert(
+            name.clone(),
+            Plug {
+                id: self.order.len(),
+                module,
+                linker,
+                instance: None,
+                deps: metadata.deps,
+                exports: metadata.exports,
+                imports: metadata.imports,
+            },
+        );
+        self.order.push(name);
+
+        Ok(())
+    }
+
+    /// Topologically sorts `self.order` so that every plugin is linked after all of its
+    /// dependencies, using a DFS with white/gray/black coloring over the `deps` graph.
+    /// Returns an error naming the full cycle path if a circular dependency is found, or
+    /// naming the missing plugin if a `deps` entry doesn't exist in `items`.
+    fn toposort(&self) -> wasmtime::Result<Vec<String>> {
+        let mut colors = HashMap::new();
+        let mut stack = Vec::new();
+        let mut sorted = Vec::with_capacity(self.order.len());
+
+        for name in self.order.iter() {
+            visit_dep(name, &self.items, &mut colors, &mut stack, &mut sorted)?;
+        }
+
+        Ok(sorted)
+    }
+
+    /// Link all plugs. Plugins are linked in dependency order regardless of the order they
+    /// were `add`ed in, and circular dependencies are reported as an error.
+    pub fn link(&mut self) -> wasmtime::Result<()> {
+        self.order = self.toposort()?;
+
+        for name in self.order.iter() {
+            let p = self.items.get_mut(name.as_str()).unwrap();
+            let deps = p.deps.clone();
+            let imports = p.imports.clone();
+            let mut to_import = Vec::new();
+
+            #[cfg(debug_assertions)]
+            println!("\n[Plugs::link]: '{name}' has {deps:?} as dependencies");
+
+            // Each import names the module it comes from directly (e.g. a plugin compiled
+            // with `#[link(wasm_import_module = "plug2")]`), so it resolves against that
+            // plugin's own instance rather than a single flat namespace.
+            for (imp_module, imp_name) in imports {
+                // A plugin may declare several `Item` specs against the same module (e.g.
+                // `plug2::mul;plug2::add`), so every dep must be checked for one matching
+                // both the module *and* the symbol, not just the first one with that module.
+                let matching_dep = deps.iter().find(|d| {
+                    d.module() == imp_module
+                        && match d {
+                            DepSpec::All(_) => true,
+                            DepSpec::Item { symbol, .. } => *symbol == imp_name,
+                        }
+                });
+
+                if matching_dep.is_none() {
+                    if deps.iter().any(|d| d.module() == imp_module) {
+                        return Err(wasmtime::Error::msg(format!(
+                            "Plugin '{name}' imports '{imp_module}::{imp_name}' but didn't declare a dependency on that symbol"
+                        )));
+                    }
+                    return Err(wasmtime::Error::msg(format!(
+                        "Plugin '{name}' imports '{imp_module}::{imp_name}' but doesn't declare '{imp_module}' as a dependency"
+                    )));
+                }
+
+                let p_dep = if let Some(p_dep) = self.items.get(&imp_module) {
+                    p_dep
+                } else {
+                    return Err(wasmtime::Error::msg(format!(
+                        "'{imp_module}' is not a valid dependency"
+                    )));
+                };
+
+                if !p_dep.exports.contains(&imp_name) {
+                    return Err(wasmtime::Error::msg(format!("Dependency '{imp_module}' doesn't have export '{imp_name}' required by plugin '{name}'")));
+                }
+
+                let inst = if let Some(inst) = &p_dep.instance {
+                    inst
+                } else {
+                    return Err(wasmtime::Error::msg(format!(
+                        "Dependency '{imp_module}' in plugin '{name}' hasn't been instantiated yet"
+                    )));
+                };
+
+                let export = if let Some(e) = inst.get_export(&mut self.store, &imp_name) {
+                    e
+                } else {
+                    return Err(wasmtime::Error::msg(format!("Dependency '{imp_module}' doesn't have export '{imp_name}' required by plugin '{name}'")));
+                };
+
+                #[cfg(debug_assertions)]
+                println!("[Plugs::link]: Will define '{imp_module}::{imp_name}' in '{name}'");
+
+                to_import.push((imp_module, imp_name, export));
+            }
+
+            let p = self.items.get_mut(name.as_str()).unwrap();
+
+            for (module, imp_name, export) in to_import {
+                p.linker
+                    .define(&mut self.store, &module, &imp_name, export)?;
+            }
+
+            p.instance = Some(p.linker.instantiate(&mut self.store, &p.module)?);
+        }
+        Ok(())
+    }
+
+    pub fn init(&mut self) -> wasmtime::Result<()> {
+        let names = self.order.clone();
+
+        for name in names {
+            if let Ok((id, init_fn)) = self.get_func_with_id::<(), ()>(&name, INIT_EXPORT) {
+                self.set_current_id(id);
+                init_fn.call(&mut self.store, ())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Convenience function calling function in a plugin and setting the plugin's id as the current
+    pub fn call<P: WasmParams, R: WasmResults>(
+        &mut self,
+        plug: &str,
+        func: &str,
+        params: P,
+    ) -> wasmtime::Result<R> {
+        let (id, f) = self.get_func_with_id(plug, func)?;
+        self.set_current_id(id);
+        f.call(&mut self.store, params)
+    }
+
+    /// Symmetric counterpart to [`Plugs::add_host_fn_serde`]: serializes `input` with
+    /// bincode, writes it into `plug`'s own memory through its `__alloc` export, calls
+    /// `func` with the resulting `(ptr, len)`, and deserializes the `(ptr, len)` it returns
+    /// (packed into a single `i64`) back into `Out`.
+    pub fn call_serde<In, Out>(
+        &mut self,
+        plug: &str,
+        func: &str,
+        input: In,
+    ) -> wasmtime::Result<Out>
+    where
+        In: Serialize,
+        Out: DeserializeOwned,
+    {
+        let (id, alloc) = self.get_func_with_id::<u32, u32>(plug, ALLOC_EXPORT)?;
+        let (_, f) = self.get_func_with_id::<(i32, i32), i64>(plug, func)?;
+        self.set_current_id(id);
+
+        let bytes = bincode::serialize(&input)?;
+        let in_ptr = alloc.call(&mut self.store, bytes.len() as u32)?;
+
+        let p = self
+            .items
+            .get(plug)
+            .ok_or_else(|| wasmtime::Error::msg(format!("Couldn't find plugin '{plug}'")))?;
+        let inst = p.instance.as_ref().ok_or_else(|| {
+            wasmtime::Error::msg(format!("Plugin '{plug}' hasn't been instantiated yet"))
+        })?;
+        let memory = inst
+            .get_memory(&mut self.store, MEMORY_EXPORT)
+            .ok_or_else(|| {
+                wasmtime::Error::msg(format!(
+                    "Couldn't find '{MEMORY_EXPORT}' export in plugin '{plug}'"
+                ))
+            })?;
+        memory.write(&mut self.store, in_ptr as usize, &bytes)?;
+
+        let packed = f.call(&mut self.store, (in_ptr as i32, bytes.len() as i32))?;
+        let (out_ptr, out_len) = unpack_ptr_len(packed);
+
+        let mut out_buf = vec![0u8; out_len as usize];
+        memory.read(&mut self.store, out_ptr as usize, &mut out_buf)?;
+
+        Ok(bincode::deserialize(&out_buf)?)
+    }
+
+    /// Must be set before calling any function
+    pub fn set_current_id(&mut self, plugin_id: PlugId) {
+        self.store.data_mut().0 = plugin_id;
+    }
+
+    /// Gets id of plugin by name
+    pub fn get_plug_id(&self, name: &str) -> Option<PlugId> {
+        if let Some(p) = self.items.get(name) {
+            return Some(p.id);
+        }
+        None
+    }
+
+    /// Looks up a function in the specified plugin and returns the id of the plugin and the function
+    pub fn get_func_with_id<P: WasmParams, R: WasmResults>(
+        &mut self,
+        plug: &str,
+        func: &str,
+    ) -> wasmtime::Result<(PlugId, TypedFunc<P, R>)> {
+        if let Some(p) = self.items.get(plug) {
+            if let Some(inst) = &p.instance {
+                inst.get_typed_func::<P, R>(&mut self.store, func)
+                    .map(|f| (p.id, f))
+            } else {
+                Err(wasmtime::Error::msg(format!(
+                    "Plugin '{plug}' hasn't been instantiated yet"
+                )))
+            }
+        } else {
+            Err(wasmtime::Error::msg(format!(
+                "Couldn't find function '{func}' in plugin '{plug}'"
+            )))
+        }
+    }
+
+    pub fn get_plug_mut(&mut self, name: &str) -> Option<&mut Plug<T>> {
+        self.items.get_mut(name)
+    }
+
+    pub fn get_plug(&self, name: &str) -> Option<&Plug<T>> {
+        self.items.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The smallest valid wasm module (just the magic number and version), enough to fill
+    /// `Plug::module` for graph-logic tests that never instantiate it.
+    fn dummy_module(engine: &Engine) -> Module {
+        Module::new(engine, b"\0asm\x01\x00\x00\x00").unwrap()
+    }
+
+    fn dummy_plug(engine: &Engine, deps: Vec<DepSpec>) -> Plug<()> {
+        Plug {
+            id: 0,
+            module: dummy_module(engine),
+            linker: Linker::new(engine),
+            instance: None,
+            deps,
+            exports: Vec::new(),
+            imports: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn toposort_detects_a_cycle() {
+        let engine = Engine::default();
+        let mut items = HashMap::new();
+        items.insert(
+            "a".to_string(),
+            dummy_plug(&engine, vec![DepSpec::All("b".to_string())]),
+        );
+        items.insert(
+            "b".to_string(),
+            dummy_plug(&engine, vec![DepSpec::All("a".to_string())]),
+        );
+
+        let mut colors = HashMap::new();
+        let mut stack = Vec::new();
+        let mut sorted = Vec::new();
+        let err = visit_dep("a", &items, &mut colors, &mut stack, &mut sorted).unwrap_err();
+        assert!(err.to_string().contains("a -> b -> a"));
+    }
+
+    #[test]
+    fn toposort_reports_a_missing_dependency() {
+        let engine = Engine::default();
+        let mut items = HashMap::new();
+        items.insert(
+            "a".to_string(),
+            dummy_plug(&engine, vec![DepSpec::All("missing".to_string())]),
+        );
+
+        let mut colors = HashMap::new();
+        let mut stack = Vec::new();
+        let mut sorted = Vec::new();
+        let err = visit_dep("a", &items, &mut colors, &mut stack, &mut sorted).unwrap_err();
+        assert!(err.to_string().contains("missing"));
+    }
+
+    #[test]
+    fn toposort_orders_dependencies_before_dependents() {
+        let engine = Engine::default();
+        let mut items = HashMap::new();
+        items.insert(
+            "a".to_string(),
+            dummy_plug(&engine, vec![DepSpec::All("b".to_string())]),
+        );
+        items.insert("b".to_string(), dummy_plug(&engine, Vec::new()));
+
+        let mut colors = HashMap::new();
+        let mut stack = Vec::new();
+        let mut sorted = Vec::new();
+        visit_dep("a", &items, &mut colors, &mut stack, &mut sorted).unwrap();
+        assert_eq!(sorted, vec!["b".to_string(), "a".to_string()]);
+    }
+
+    #[test]
+    fn dep_spec_parses_bare_module_as_all() {
+        assert_eq!(DepSpec::parse("plug2"), DepSpec::All("plug2".to_string()));
+    }
+
+    #[test]
+    fn dep_spec_parses_wildcard_as_all() {
+        assert_eq!(
+            DepSpec::parse("plug2::*"),
+            DepSpec::All("plug2".to_string())
+        );
+    }
+
+    #[test]
+    fn dep_spec_parses_symbol_as_item() {
+        assert_eq!(
+            DepSpec::parse("plug2::mul"),
+            DepSpec::Item {
+                module: "plug2".to_string(),
+                symbol: "mul".to_string(),
+            }
+        );
+    }
+}